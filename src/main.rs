@@ -2,10 +2,23 @@ use std::cmp;
 use std::env;
 use std::fs;
 use std::io;
+use std::io::BufRead;
 
 use std::collections::HashSet;
 use std::fmt::Write;
 
+use rayon::prelude::*;
+use thiserror::Error;
+use unicode_width::UnicodeWidthChar;
+
+const CHUNK_SIZE: usize = 16 * 1024;
+
+// Tabs advance the display column to the next multiple of this width.
+const TAB_STOP: usize = 8;
+
+// Up to this many files, the thread-pool setup isn't worth it.
+const PARALLEL_THRESHOLD: usize = 8;
+
 const FMT_DISPLAY_WIDTH: usize = 6;
 
 #[derive(Debug, Eq, Hash, PartialEq, Clone)]
@@ -17,6 +30,26 @@ enum WcCliOpt {
     MaxLineLength,
 }
 
+#[derive(Debug, Error)]
+enum WcError {
+    #[error("{0}: No such file or directory")]
+    NotFound(String),
+    #[error("{0}: Is a directory")]
+    IsDirectory(String),
+    #[error("{0}: cannot open: {1}")]
+    CannotOpen(String, io::Error),
+    #[error("{0}: {1}")]
+    ReadFailed(String, io::Error),
+    #[error("{0}: invalid byte sequence")]
+    InvalidUtf8(String),
+    #[error("{0}:{1}: invalid zero-length file name")]
+    EmptyFileName(String, usize),
+    #[error("file operands cannot be combined with --files0-from")]
+    OperandsWithFiles0From,
+    #[error("when reading file names from stdin, no file name of '-' allowed")]
+    StdinFiles0FromConflict,
+}
+
 fn version() {
     println!(
         r#"ccwc 0.1.0
@@ -61,23 +94,75 @@ Options:
     )
 }
 
+fn is_shell_safe_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/' | ':' | '@' | '%' | '+' | '=' | ',')
+}
+
+// Quotes a filename for display the way GNU coreutils does: names made up
+// only of "safe" characters are printed bare, names containing shell-special
+// or nonprintable characters are wrapped in single quotes, and names
+// containing a single quote or a control byte switch to `$'...'` escaping.
+fn quote_filename(name: &str) -> String {
+    if !name.is_empty() && name.chars().all(is_shell_safe_char) {
+        return name.to_string();
+    }
+
+    if name.chars().any(|c| c == '\'' || c.is_control()) {
+        let mut escaped = String::from("$'");
+        for c in name.chars() {
+            match c {
+                '\'' => escaped.push_str("\\'"),
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                '\t' => escaped.push_str("\\t"),
+                '\r' => escaped.push_str("\\r"),
+                c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+                c => escaped.push(c),
+            }
+        }
+        escaped.push('\'');
+        escaped
+    } else {
+        format!("'{}'", name)
+    }
+}
+
 fn invalid_opt(opt: &str) {
     let (_, opt) = opt.split_at("-".len());
-    println!(
+    eprintln!(
         "ccwc: invalid option -- '{}'",
         &opt[..cmp::min(opt.len(), 1)]
     );
-    println!("Try 'ccwc --help' for more information");
+    eprintln!("Try 'ccwc --help' for more information");
 }
 
-fn add_input_files(opt: &str, files: &mut Vec<String>) {
-    let (_, input) = opt.split_at("--files0-from=".len());
-    let contents = if input == "-" {
-        io::read_to_string(io::stdin()).expect("Failed to read from stdin")
+fn add_input_files(source: &str, files: &mut Vec<String>) -> Result<(), WcError> {
+    let contents = if source == "-" {
+        io::read_to_string(io::stdin()).map_err(|e| WcError::ReadFailed("-".to_string(), e))?
     } else {
-        fs::read_to_string(input).expect(&format!("Failed to read from {}", input))
+        fs::read_to_string(source)
+            .map_err(|e| WcError::CannotOpen(quote_filename(source), e))?
     };
-    files.extend(contents.split('\0').map(String::from));
+
+    let mut records: Vec<&str> = contents.split('\0').collect();
+    // A correctly NUL-terminated list ends with an empty trailing segment
+    // after the final terminator; drop it rather than treating it as a
+    // blank file name.
+    if records.last() == Some(&"") {
+        records.pop();
+    }
+
+    for (index, name) in records.iter().enumerate() {
+        if name.is_empty() {
+            return Err(WcError::EmptyFileName(quote_filename(source), index + 1));
+        }
+        if source == "-" && *name == "-" {
+            return Err(WcError::StdinFiles0FromConflict);
+        }
+        files.push(name.to_string());
+    }
+
+    Ok(())
 }
 
 fn wc_fmt(counts: &Vec<usize>) -> String {
@@ -100,31 +185,177 @@ fn add_option(opt: WcCliOpt, opts: &mut Vec<WcCliOpt>, seen: &mut HashSet<WcCliO
     }
 }
 
-fn wc(contents: &String, opts: &Vec<WcCliOpt>) -> Vec<usize> {
-    let mut counts: Vec<usize> = Vec::new();
+// Running totals kept across read-buffer boundaries so a file can be counted
+// incrementally without ever holding its full contents in memory.
+struct WcCounts {
+    lines: usize,
+    words: usize,
+    bytes: usize,
+    chars: usize,
+    max_line_length: usize,
+    cur_line_length: usize,
+    prev_is_space: bool,
+    // Bytes of a UTF-8 sequence that was cut off at the end of a chunk,
+    // carried over until the rest of the sequence arrives.
+    leftover: Vec<u8>,
+    // Whether any requested option needs char-level data (CountCharacters,
+    // MaxLineLength). Byte/line/word counting never needs valid UTF-8, so
+    // skip decoding entirely when it's not asked for.
+    track_chars: bool,
+}
+
+impl WcCounts {
+    fn new(opts: &[WcCliOpt]) -> Self {
+        WcCounts {
+            lines: 0,
+            words: 0,
+            bytes: 0,
+            chars: 0,
+            max_line_length: 0,
+            cur_line_length: 0,
+            prev_is_space: true,
+            leftover: Vec::new(),
+            track_chars: opts.contains(&WcCliOpt::CountCharacters)
+                || opts.contains(&WcCliOpt::MaxLineLength),
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.bytes += chunk.len();
+
+        for &b in chunk {
+            if b == b'\n' {
+                self.lines += 1;
+            }
+
+            if b.is_ascii_whitespace() {
+                self.prev_is_space = true;
+            } else {
+                if self.prev_is_space {
+                    self.words += 1;
+                }
+                self.prev_is_space = false;
+            }
+        }
+
+        if !self.track_chars {
+            return Ok(());
+        }
+
+        self.leftover.extend_from_slice(chunk);
+
+        // error_len() is None for a sequence merely truncated at the end of
+        // this chunk, Some(_) for a genuinely invalid byte.
+        let (valid_up_to, invalid) = match std::str::from_utf8(&self.leftover) {
+            Ok(s) => (s.len(), false),
+            Err(e) => (e.valid_up_to(), e.error_len().is_some()),
+        };
+
+        // Draining into an owned buffer (rather than counting from a `&str`
+        // borrowed out of `self.leftover`) lets `count_chars` take `&mut
+        // self` without a borrow-checker conflict.
+        let valid: Vec<u8> = self.leftover.drain(..valid_up_to).collect();
+        let s = std::str::from_utf8(&valid).unwrap();
+        self.count_chars(s);
 
-    for opt in opts.iter() {
-        match opt {
-            WcCliOpt::CountBytes => counts.push(contents.bytes().len()),
-            WcCliOpt::CountCharacters => counts.push(contents.chars().count()),
-            WcCliOpt::CountLines => counts.push(contents.lines().count()),
-            WcCliOpt::MaxLineLength => {
-                counts.push(contents.lines().map(|line| line.len()).max().unwrap_or(0))
+        if invalid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid byte sequence",
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Counts characters and tracks the display width of the current line,
+    // expanding tabs to the next multiple of `TAB_STOP` columns.
+    fn count_chars(&mut self, s: &str) {
+        for c in s.chars() {
+            self.chars += 1;
+            match c {
+                '\n' => {
+                    self.max_line_length = cmp::max(self.max_line_length, self.cur_line_length);
+                    self.cur_line_length = 0;
+                }
+                '\t' => self.cur_line_length = (self.cur_line_length / TAB_STOP + 1) * TAB_STOP,
+                c => self.cur_line_length += c.width().unwrap_or(0),
             }
-            WcCliOpt::CountWords => counts.push(
-                contents
-                    .lines()
-                    .map(|line| line.split_whitespace().count())
-                    .sum(),
-            ),
         }
     }
-    counts
+
+    fn finish(mut self) -> io::Result<Vec<(WcCliOpt, usize)>> {
+        self.max_line_length = cmp::max(self.max_line_length, self.cur_line_length);
+        if !self.leftover.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid byte sequence",
+            ));
+        }
+        Ok(vec![
+            (WcCliOpt::CountBytes, self.bytes),
+            (WcCliOpt::CountCharacters, self.chars),
+            (WcCliOpt::CountLines, self.lines),
+            (WcCliOpt::MaxLineLength, self.max_line_length),
+            (WcCliOpt::CountWords, self.words),
+        ])
+    }
 }
 
-fn main() {
-    // Files
-    let mut files: Vec<String> = Vec::new();
+fn wc<R: BufRead>(mut reader: R, opts: &[WcCliOpt]) -> io::Result<Vec<usize>> {
+    let mut counts = WcCounts::new(opts);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        counts.push(&buf[..n])?;
+    }
+
+    let totals = counts.finish()?;
+    Ok(opts
+        .iter()
+        .map(|opt| totals.iter().find(|(o, _)| o == opt).unwrap().1)
+        .collect())
+}
+
+fn count_file(file: &str, opts: &Vec<WcCliOpt>) -> Result<Vec<usize>, WcError> {
+    let metadata = fs::metadata(file).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => WcError::NotFound(quote_filename(file)),
+        _ => WcError::CannotOpen(quote_filename(file), e),
+    })?;
+
+    if metadata.is_dir() {
+        return Err(WcError::IsDirectory(quote_filename(file)));
+    }
+
+    if metadata.is_file() && metadata.len() > 0 && opts.as_slice() == [WcCliOpt::CountBytes] {
+        // Byte count of a regular file is already known from its
+        // metadata, so skip opening and reading it entirely. Pipes and
+        // other non-regular files don't report a trustworthy size here,
+        // and pseudo-files like /proc entries report a regular file with
+        // a zero size regardless of their actual contents, so fall
+        // through to actually reading in both cases.
+        return Ok(vec![metadata.len() as usize]);
+    }
+
+    let reader = fs::File::open(file)
+        .map(io::BufReader::new)
+        .map_err(|e| WcError::CannotOpen(quote_filename(file), e))?;
+
+    wc(reader, opts).map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidData => WcError::InvalidUtf8(quote_filename(file)),
+        _ => WcError::ReadFailed(quote_filename(file), e),
+    })
+}
+
+fn run() -> i32 {
+    // Explicit file operands given on the command line.
+    let mut operands: Vec<String> = Vec::new();
+    // The --files0-from=F source, if given.
+    let mut files0_from: Option<String> = None;
 
     // CLI Options
     let mut read_stdin = false;
@@ -132,7 +363,7 @@ fn main() {
 
     // Parsing
     let mut seen: HashSet<WcCliOpt> = HashSet::new();
-    for arg in env::args().skip(1).into_iter() {
+    for arg in env::args().skip(1) {
         match arg.as_str() {
             "-c" | "--bytes" => add_option(WcCliOpt::CountBytes, &mut opts, &mut seen),
             "-m" | "--chars" => add_option(WcCliOpt::CountCharacters, &mut opts, &mut seen),
@@ -140,11 +371,23 @@ fn main() {
             "-L" | "--max-line-length" => add_option(WcCliOpt::MaxLineLength, &mut opts, &mut seen),
             "-w" | "--words" => add_option(WcCliOpt::CountWords, &mut opts, &mut seen),
             "-" => read_stdin = true,
-            "--version" => return version(),
-            "--help" => return help(),
-            s if s.starts_with("--files0-from=") => add_input_files(s, &mut files),
-            s if s.starts_with("-") => return invalid_opt(s),
-            file => files.push(file.to_string()),
+            "--version" => {
+                version();
+                return 0;
+            }
+            "--help" => {
+                help();
+                return 0;
+            }
+            s if s.starts_with("--files0-from=") => {
+                let (_, source) = s.split_at("--files0-from=".len());
+                files0_from = Some(source.to_string());
+            }
+            s if s.starts_with("-") => {
+                invalid_opt(s);
+                return 1;
+            }
+            file => operands.push(file.to_string()),
         }
     }
 
@@ -157,37 +400,70 @@ fn main() {
         ];
     }
 
+    let mut files: Vec<String> = Vec::new();
+    if let Some(source) = files0_from {
+        if !operands.is_empty() || read_stdin {
+            eprintln!("ccwc: {}", WcError::OperandsWithFiles0From);
+            return 1;
+        }
+        if let Err(e) = add_input_files(&source, &mut files) {
+            eprintln!("ccwc: {}", e);
+            return 1;
+        }
+    } else {
+        files = operands;
+    }
+
+    let mut error_count = 0usize;
+
     // Implementation
     if read_stdin || files.is_empty() {
         let file = if read_stdin { "-" } else { "" };
-        let contents = io::read_to_string(io::stdin()).expect("Unable to read from stdin");
-        println!(
-            "{}{:>FMT_DISPLAY_WIDTH$}",
-            wc_fmt(&wc(&contents, &opts)),
-            file
-        );
+        match wc(io::stdin().lock(), &opts) {
+            Ok(counts) => println!("{}{:>FMT_DISPLAY_WIDTH$}", wc_fmt(&counts), file),
+            Err(e) => {
+                let message = match e.kind() {
+                    io::ErrorKind::InvalidData => "invalid byte sequence".to_string(),
+                    _ => e.to_string(),
+                };
+                eprintln!("ccwc: {}: {}", file, message);
+                error_count += 1;
+            }
+        }
     }
 
+    let results: Vec<(&String, Result<Vec<usize>, WcError>)> = if files.len() > PARALLEL_THRESHOLD
+    {
+        files
+            .par_iter()
+            .map(|file| (file, count_file(file, &opts)))
+            .collect()
+    } else {
+        files
+            .iter()
+            .map(|file| (file, count_file(file, &opts)))
+            .collect()
+    };
+
     let mut total: Vec<usize> = Vec::new();
-    for file in files.iter() {
-        if let Ok(metadata) = fs::metadata(file) {
-            let contents = if metadata.is_file() {
-                fs::read_to_string(file).expect(&format!("Unable to read {}", file))
-            } else {
-                println!("ccwc: {}: Is a directory", file);
-                String::new()
-            };
-            let counts = wc(&contents, &opts);
-            println!("{}{:>FMT_DISPLAY_WIDTH$}", wc_fmt(&counts), file);
-
-            // Update total count
-            if total.is_empty() {
-                total = counts;
-            } else {
-                total = total.iter().zip(&counts).map(|(&t, &c)| t + c).collect();
+    for (file, result) in results {
+        match result {
+            Ok(counts) => {
+                println!(
+                    "{}{:>FMT_DISPLAY_WIDTH$}",
+                    wc_fmt(&counts),
+                    quote_filename(file)
+                );
+                if total.is_empty() {
+                    total = counts;
+                } else {
+                    total = total.iter().zip(&counts).map(|(&t, &c)| t + c).collect();
+                }
+            }
+            Err(e) => {
+                eprintln!("ccwc: {}", e);
+                error_count += 1;
             }
-        } else {
-            println!("ccwc: {}: No such file or directory", file);
         }
     }
 
@@ -195,4 +471,14 @@ fn main() {
     if files.len() > 1 {
         println!("{}total", wc_fmt(&total));
     }
+
+    if error_count > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+fn main() {
+    std::process::exit(run());
 }